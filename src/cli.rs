@@ -1,13 +1,15 @@
-use crate::kmeans::model::{Dataset, Trainer};
+use crate::encode;
+use crate::kmeans::model::{Dataset, Model, Trainer};
 use clap::Parser;
 use image::buffer::ConvertBuffer;
 use image::codecs::jpeg::JpegEncoder;
-use image::{GenericImageView, ImageBuffer, ImageReader, RgbImage, Rgba};
+use image::{DynamicImage, GenericImageView, ImageBuffer, ImageReader, RgbImage, Rgba};
 use std::cmp::{max, min};
 use std::error::Error;
 use std::fs::File;
 use std::path::{Path, PathBuf};
-use std::sync::{Arc, Mutex};
+use std::sync::mpsc::SyncSender;
+use std::sync::{mpsc, Arc, Mutex};
 use std::thread::JoinHandle;
 use std::time::Instant;
 use std::{fs, thread};
@@ -15,6 +17,11 @@ use tracing::{debug, error, info};
 
 const PHI: f32 = 1.618033988749894848204586834365638118_f32;
 
+// Bounds the decode->cluster and cluster->encode channels.
+const CHANNEL_BOUND: usize = 4;
+// I/O-bound stages get their own small pool instead of scaling with --concurrency.
+const IO_THREADS: usize = 2;
+
 #[derive(Parser)]
 #[command(name = "kcomprs", about = "Reduce number of colors used in image")]
 pub struct Cli {
@@ -56,7 +63,10 @@ pub struct Cli {
     )]
     concurrency: usize,
 
-    #[arg(long = "kcpu", help = "Maximum cpu used processing each image [unsupported]")]
+    #[arg(
+        long = "kcpu",
+        help = "Maximum cpu used processing each image's kmeans, composes with --concurrency [requires the \"parallel\" feature]"
+    )]
     kmeans_concurrency: Option<usize>,
 
     #[arg(
@@ -70,7 +80,7 @@ pub struct Cli {
     #[arg(
         long = "dalgo",
         default_value = "EuclideanDistance",
-        help = "Distance algo for kmeans [EuclideanDistance,EuclideanDistanceSquared]"
+        help = "Distance algo for kmeans [EuclideanDistance,EuclideanDistanceSquared,CIELAB]"
     )]
     distance_algo: String,
 
@@ -83,6 +93,27 @@ pub struct Cli {
     #[arg(long, action, help = "Generate an additional palette image")]
     palette: bool,
 
+    #[arg(
+        long,
+        action,
+        help = "Write plain RGBA png instead of the smaller indexed (palette) png"
+    )]
+    rgba: bool,
+
+    #[arg(
+        long,
+        action,
+        help = "Apply lossless per-scanline filter and deflate tuning to the output png, slower but typically 10-30% smaller"
+    )]
+    optimize: bool,
+
+    #[arg(
+        long,
+        action,
+        help = "Diffuse quantization error between pixels (Floyd-Steinberg) to reduce banding at low color counts"
+    )]
+    dither: bool,
+
     #[arg(long, action, global = true, help = "Enable debug mode")]
     pub debug: bool,
 }
@@ -93,11 +124,23 @@ fn default_concurrency() -> String {
     default_concurrency.to_string()
 }
 
+// Stage 1 -> stage 2: decoded image plus its per-series-step configs.
 struct DecodedImage {
-    img: image::DynamicImage,
+    img: Arc<DynamicImage>,
     format: image::ImageFormat,
     path: String,
+    configs: Vec<ProcessImageConfig>,
+}
+
+// Stage 2 -> stage 3: fitted model plus everything the encode stage needs.
+struct ClusteredImage {
+    path: String,
+    outfile_buf: PathBuf,
     config: ProcessImageConfig,
+    width: u32,
+    height: u32,
+    model: Model,
+    start: Instant,
 }
 
 struct ProcessImageConfig {
@@ -109,6 +152,11 @@ struct ProcessImageConfig {
     distance_algo: String,
     delta: f64,
     palette: bool,
+    rgba: bool,
+    optimize: bool,
+    dither: bool,
+    #[cfg(feature = "parallel")]
+    kmeans_concurrency: Option<usize>,
 }
 
 impl Cli {
@@ -129,78 +177,92 @@ impl Cli {
     }
 
     pub fn execute(self) -> Result<(), Box<dyn Error>> {
-        let images = self.scan_images();
-        if images.is_empty() {
+        let paths = self.scan_paths();
+        if paths.is_empty() {
             return Ok(());
         }
 
-        // Avoid concurrency overhead when disabled.
-        if images.len() == 1 || self.concurrency <= 1 {
-            for image in images {
-                let res = handle_image(&image);
-                if res.is_err() {
-                    error!(error = res.unwrap_err(), path = image.path, "Error processing image");
-                }
-            }
-            return Ok(());
-        }
+        let work_threads = max(self.concurrency, 1);
+        let decode_threads = paths.len().clamp(1, IO_THREADS);
+        let encode_threads = min(IO_THREADS, work_threads);
 
-        // TODO: is there any other way to do this?
-        let images = Arc::new(Mutex::new(images));
-        thread::scope(|s| {
-            for _ in 0..self.concurrency {
-                let images = Arc::clone(&images);
-                s.spawn(move || {
-                    let mut images = images.lock().unwrap();
-                    let image = images.pop();
-                    drop(images);
-
-                    if image.is_none() {
-                        return;
-                    }
+        let paths = Arc::new(Mutex::new(paths));
+        let (decoded_tx, decoded_rx) = mpsc::sync_channel::<DecodedImage>(CHANNEL_BOUND);
+        let decoded_rx = Arc::new(Mutex::new(decoded_rx));
+        let (clustered_tx, clustered_rx) = mpsc::sync_channel::<ClusteredImage>(CHANNEL_BOUND);
+        let clustered_rx = Arc::new(Mutex::new(clustered_rx));
 
-                    let image = image.unwrap();
-                    let res = handle_image(&image);
-                    if res.is_err() {
-                        error!(error = res.unwrap_err(), path = image.path, "Error processing image");
-                    }
+        thread::scope(|s| {
+            // Stage 1: walk the remaining paths and decode them.
+            for _ in 0..decode_threads {
+                let paths = Arc::clone(&paths);
+                let decoded_tx = decoded_tx.clone();
+                let cli = &self;
+                s.spawn(move || loop {
+                    let path = paths.lock().unwrap().pop();
+                    let Some(path) = path else { break };
+                    cli.decode_path(&path, &decoded_tx);
+                });
+            }
+            drop(decoded_tx);
+
+            // Stage 2: run kmeans on each decoded image, once per series step.
+            for _ in 0..work_threads {
+                let decoded_rx = Arc::clone(&decoded_rx);
+                let clustered_tx = clustered_tx.clone();
+                s.spawn(move || loop {
+                    let decoded = decoded_rx.lock().unwrap().recv();
+                    let Ok(decoded) = decoded else { break };
+                    cluster_image(decoded, &clustered_tx);
+                });
+            }
+            drop(clustered_tx);
+
+            // Stage 3: encode and write each clustered image.
+            for _ in 0..encode_threads {
+                let clustered_rx = Arc::clone(&clustered_rx);
+                s.spawn(move || loop {
+                    let clustered = clustered_rx.lock().unwrap().recv();
+                    let Ok(clustered) = clustered else { break };
+                    write_image(clustered);
                 });
             }
         });
+
         Ok(())
     }
 
-    fn scan_images(&self) -> Vec<DecodedImage> {
-        let mut images: Vec<DecodedImage> = Vec::with_capacity(self.files.len());
+    fn scan_paths(&self) -> Vec<String> {
+        let mut paths = Vec::with_capacity(self.files.len());
         for path in &self.files {
             match fs::metadata(path) {
                 Ok(metadata) => {
                     if metadata.is_file() {
-                        self.read_images(path, &mut images);
+                        paths.push(path.clone());
                         continue;
                     }
-                    let paths = fs::read_dir(path).unwrap();
-                    for path in paths {
-                        if path.is_err() {
-                            let err: Box<dyn Error> = path.unwrap_err().into();
+                    let entries = fs::read_dir(path).unwrap();
+                    for entry in entries {
+                        if entry.is_err() {
+                            let err: Box<dyn Error> = entry.unwrap_err().into();
                             debug!(error = err, "Error reading path metadata");
                             continue;
                         }
-                        let path = path.unwrap();
-                        match path.metadata() {
+                        let entry = entry.unwrap();
+                        match entry.metadata() {
                             Ok(metadata) => {
                                 if !metadata.is_file() {
                                     continue;
                                 }
-                                let path = path.path();
-                                let path = path.to_str();
-                                if path.is_some() {
-                                    self.read_images(path.unwrap(), &mut images);
+                                let entry = entry.path();
+                                let entry = entry.to_str();
+                                if entry.is_some() {
+                                    paths.push(entry.unwrap().to_string());
                                 }
                             }
                             Err(err) => {
                                 let err: Box<dyn Error> = err.into();
-                                error!(path = path.path().to_str(), error = err, "Error reading file metadata");
+                                error!(path = entry.path().to_str(), error = err, "Error reading file metadata");
                                 continue;
                             }
                         }
@@ -213,11 +275,11 @@ impl Cli {
                 }
             }
         }
-        images
+        paths
     }
 
-    fn read_images(&self, path: &str, images: &mut Vec<DecodedImage>) {
-        let reader = ImageReader::open(&path);
+    fn decode_path(&self, path: &str, tx: &SyncSender<DecodedImage>) {
+        let reader = ImageReader::open(path);
         if reader.is_err() {
             error!(
                 path = &path,
@@ -251,8 +313,23 @@ impl Cli {
         }
         let img = img.unwrap();
 
-        if !self.series.is_none() {
-            let mut s = self.series.unwrap();
+        let send_result = tx.send(DecodedImage {
+            img: Arc::new(img),
+            format: format.unwrap(),
+            path: path.to_string(),
+            configs: self.build_configs(),
+        });
+        if send_result.is_err() {
+            debug!(path = &path, "Dropped decoded image, work stage has shut down");
+        }
+    }
+
+    // --series steps ride through stage 2 together now, so they run sequentially
+    // per image instead of being stolen individually across the worker pool.
+    fn build_configs(&self) -> Vec<ProcessImageConfig> {
+        let mut configs = Vec::new();
+        if let Some(series) = self.series {
+            let mut s = series;
             let mut step = self.colors / s;
             let mut start = 1;
             if step <= 1 {
@@ -264,22 +341,11 @@ impl Cli {
             for i in start..s {
                 let mut config: ProcessImageConfig = self.into();
                 config.colors = step * i;
-                images.push(DecodedImage {
-                    // TODO: any better way instead of cloning?
-                    img: img.clone(),
-                    format: format.unwrap(),
-                    path: path.to_string(),
-                    config,
-                })
+                configs.push(config);
             }
         }
-
-        images.push(DecodedImage {
-            img,
-            format: format.unwrap(),
-            path: path.to_string(),
-            config: self.into(),
-        })
+        configs.push(self.into());
+        configs
     }
 }
 
@@ -294,104 +360,141 @@ impl Into<ProcessImageConfig> for &Cli {
             distance_algo: self.distance_algo.clone(),
             delta: self.delta,
             palette: self.palette,
+            rgba: self.rgba,
+            optimize: self.optimize,
+            dither: self.dither,
+            #[cfg(feature = "parallel")]
+            kmeans_concurrency: self.kmeans_concurrency,
         }
     }
 }
 
-fn handle_image(image: &DecodedImage) -> Result<(), Box<dyn Error>> {
-    let filepath = Path::new(&image.path);
+fn cluster_image(decoded: DecodedImage, tx: &SyncSender<ClusteredImage>) {
+    let filepath = Path::new(&decoded.path);
     let filename = filepath.file_name().expect("Missing filename what the fuck").to_str();
     if filename.is_none() {
-        return Err(format!("Invalid filename: {}", filepath.display()).into());
+        error!(path = &decoded.path, "Invalid filename");
+        return;
     }
     let filename = filename.unwrap();
 
-    let format = image.format.extensions_str().join("|");
-    info!(
-        cp = image.config.colors,
-        round = image.config.round,
-        img = filename,
-        dimension = format!("{}x{}", image.img.width(), image.img.height()),
-        format = format,
-        "Processing image"
-    );
+    let width = decoded.img.width();
+    let height = decoded.img.height();
+    let format = decoded.format.extensions_str().join("|");
+
+    for config in decoded.configs {
+        info!(
+            cp = config.colors,
+            round = config.round,
+            img = filename,
+            dimension = format!("{}x{}", width, height),
+            format = format,
+            "Processing image"
+        );
+
+        let outfile_buf = resolve_outfile(filename, &config);
+        let outfile = outfile_buf.to_str().unwrap();
+
+        if let Ok(metadata) = fs::metadata(outfile) {
+            info!(
+                path = outfile,
+                isDir = metadata.is_dir(),
+                overwrite = config.overwrite,
+                "File existed"
+            );
+            if !config.overwrite || !metadata.is_file() {
+                continue;
+            }
+        }
+
+        let start = Instant::now();
+        let mut matrix = Vec::with_capacity((width * height) as usize);
+        decoded.img.pixels().for_each(|pixel| {
+            matrix.push([
+                pixel.2[0] as f64,
+                pixel.2[1] as f64,
+                pixel.2[2] as f64,
+                pixel.2[3] as f64,
+            ])
+        });
+
+        let mut srgb_matrix = if config.dither { Some(matrix.clone()) } else { None };
 
+        let use_lab = config.distance_algo == "CIELAB";
+        if use_lab {
+            for pixel in matrix.iter_mut() {
+                *pixel = crate::kmeans::cluster::srgb_to_lab(pixel);
+            }
+        }
+
+        debug!(
+            cp = config.colors,
+            img = filename,
+            round = config.round,
+            ms = start.elapsed().as_millis(),
+            "Start partitioning"
+        );
+        let trainer = Trainer {
+            k: config.colors,
+            max_iterations: config.round,
+            delta: config.delta,
+            distance_fn: match config.distance_algo.as_str() {
+                // TODO: enum, maybe?.
+                "EuclideanDistanceSquared" => crate::kmeans::cluster::euclidean_distance_squared,
+                _ => crate::kmeans::cluster::euclidean_distance,
+            },
+            #[cfg(feature = "parallel")]
+            kcpu: config.kmeans_concurrency,
+        };
+
+        let mut model = trainer.fit(matrix);
+        if use_lab {
+            for centroid in model.centroids.iter_mut() {
+                *centroid = crate::kmeans::cluster::lab_to_srgb(centroid);
+            }
+        }
+        if let Some(srgb_matrix) = srgb_matrix.take() {
+            model.mapping = model.dither(srgb_matrix, width, height);
+        }
+
+        let send_result = tx.send(ClusteredImage {
+            path: decoded.path.clone(),
+            outfile_buf,
+            config,
+            width,
+            height,
+            model,
+            start,
+        });
+        if send_result.is_err() {
+            debug!(path = &decoded.path, "Dropped clustered image, encode stage has shut down");
+        }
+    }
+}
+
+fn resolve_outfile(filename: &str, config: &ProcessImageConfig) -> PathBuf {
     let mut outfile_buf = PathBuf::new();
-    if !image.config.output.is_none() {
-        let output = image.config.output.as_ref();
-        outfile_buf.push(output.unwrap());
+    if let Some(output) = &config.output {
+        outfile_buf.push(output);
     }
     outfile_buf.push(filename);
     outfile_buf.set_extension("");
     outfile_buf.set_file_name(format!(
         "{}.kcp{}n{}.",
         outfile_buf.file_name().unwrap().to_str().unwrap(),
-        image.config.round,
-        image.config.colors
+        config.round,
+        config.colors
     ));
-    outfile_buf.set_extension(if image.config.jpeg > 0 { "jpeg" } else { "png" });
-    let outfile = outfile_buf.to_str().unwrap();
-
-    if let Ok(metadata) = fs::metadata(outfile) {
-        info!(
-            path = outfile,
-            isDir = metadata.is_dir(),
-            overwrite = image.config.overwrite,
-            "File existed"
-        );
-        if !image.config.overwrite {
-            return Ok(());
-        }
-        if !metadata.is_file() {
-            return Ok(());
-        }
-    }
-
-    let start = Instant::now();
-    let mut matrix = Vec::with_capacity((image.img.width() * image.img.height()) as usize);
-    image.img.pixels().for_each(|pixel| {
-        matrix.push([
-            pixel.2[0] as f64,
-            pixel.2[1] as f64,
-            pixel.2[2] as f64,
-            pixel.2[3] as f64,
-        ])
-    });
-
-    debug!(
-        cp = image.config.colors,
-        img = filename,
-        round = image.config.round,
-        ms = start.elapsed().as_millis(),
-        "Start partitioning"
-    );
-    let trainer = Trainer {
-        k: image.config.colors,
-        max_iterations: image.config.round,
-        delta: image.config.delta,
-        distance_fn: match image.config.distance_algo.as_str() {
-            // TODO: enum, maybe?.
-            "EuclideanDistanceSquared" => crate::kmeans::cluster::euclidean_distance_squared,
-            _ => crate::kmeans::cluster::euclidean_distance,
-        },
-    };
-
-    let model = trainer.fit(matrix);
+    outfile_buf.set_extension(if config.jpeg > 0 { "jpeg" } else { "png" });
+    outfile_buf
+}
 
-    let width = image.img.width();
-    let mut img = ImageBuffer::<Rgba<u8>, Vec<u8>>::new(width, image.img.height());
-    for (index, number) in model.mapping.iter().enumerate() {
-        let cluster = model.centroids[*number];
-        let y = index as u32 / width;
-        let x = index as u32 % width;
-        let r = cluster[0].round() as u8;
-        let g = cluster[1].round() as u8;
-        let b = cluster[2].round() as u8;
-        let a = cluster[3].round() as u8;
-        img.put_pixel(x, y, Rgba([r, g, b, a]));
-    }
+fn write_image(image: ClusteredImage) {
+    let outfile_buf = image.outfile_buf;
+    let outfile = outfile_buf.to_str().unwrap();
+    let model = &image.model;
 
-    let write_result = if image.config.jpeg > 0 {
+    let write_result: Result<(), Box<dyn Error>> = if image.config.jpeg > 0 {
         let file = File::create(outfile);
         match file {
             Err(err) => {
@@ -401,33 +504,59 @@ fn handle_image(image: &DecodedImage) -> Result<(), Box<dyn Error>> {
             }
             Ok(file) => {
                 let encoder = JpegEncoder::new_with_quality(file, image.config.jpeg as u8);
-                let img: RgbImage = img.convert();
-                img.write_with_encoder(encoder)
+                let img: RgbImage = rgba_buffer(image.width, image.height, model).convert();
+                img.write_with_encoder(encoder).map_err(Into::into)
             }
         }
+    } else if !image.config.rgba && encode::fits_indexed_palette(model.centroids.len()) {
+        encode::write_indexed_png(
+            Path::new(outfile),
+            image.width,
+            image.height,
+            &model.centroids,
+            &model.mapping,
+            image.config.optimize,
+        )
     } else {
-        img.save(outfile)
+        encode::write_rgba_png(
+            Path::new(outfile),
+            &rgba_buffer(image.width, image.height, model),
+            image.config.optimize,
+        )
     };
 
     match write_result {
         Ok(_) => {
             let outfile = outfile.to_owned();
             if image.config.palette {
-                gen_palette(model.centroids, outfile_buf)
+                gen_palette(image.model.centroids, outfile_buf)
             }
             info!(
                 out = outfile,
-                ms = start.elapsed().as_millis(),
-                iter = model.iter,
+                ms = image.start.elapsed().as_millis(),
+                iter = image.model.iter,
                 "Compress completed"
             );
         }
         Err(err) => {
-            let err: Box<dyn Error> = err.into();
-            error!(error = err, out = outfile, "Error writing image");
+            error!(error = err, path = image.path, out = outfile, "Error writing image");
         }
     }
-    Ok(())
+}
+
+fn rgba_buffer(width: u32, height: u32, model: &Model) -> ImageBuffer<Rgba<u8>, Vec<u8>> {
+    let mut img = ImageBuffer::<Rgba<u8>, Vec<u8>>::new(width, height);
+    for (index, number) in model.mapping.iter().enumerate() {
+        let cluster = model.centroids[*number];
+        let y = index as u32 / width;
+        let x = index as u32 % width;
+        let r = cluster[0].round() as u8;
+        let g = cluster[1].round() as u8;
+        let b = cluster[2].round() as u8;
+        let a = cluster[3].round() as u8;
+        img.put_pixel(x, y, Rgba([r, g, b, a]));
+    }
+    img
 }
 
 fn gen_palette(centroids: Dataset, mut outfile: PathBuf) {
@@ -0,0 +1,122 @@
+use crate::kmeans::model::Dataset;
+use image::{ImageBuffer, Rgba};
+use png::{BitDepth, ColorType, Compression, Encoder, Filter};
+use std::error::Error;
+use std::fs::File;
+use std::io::BufWriter;
+use std::path::Path;
+
+// oxipng-style per-scanline filter picking + higher deflate effort.
+// The adaptive (MSAD) filter heuristic is the expensive part, so the fast
+// path explicitly opts into a cheap fixed filter instead of relying on
+// png's own default.
+fn apply_optimization(encoder: &mut Encoder<BufWriter<File>>, optimize: bool) {
+    if optimize {
+        encoder.set_compression(Compression::High);
+        encoder.set_filter(Filter::Adaptive);
+    } else {
+        encoder.set_compression(Compression::Balanced);
+        encoder.set_filter(Filter::Up);
+    }
+}
+
+pub const MAX_PALETTE_SIZE: usize = 256;
+
+fn bit_depth_for(palette_size: usize) -> BitDepth {
+    match palette_size {
+        0..=2 => BitDepth::One,
+        3..=4 => BitDepth::Two,
+        5..=16 => BitDepth::Four,
+        _ => BitDepth::Eight,
+    }
+}
+
+// Bit-pack one row of palette indices MSB-first for the given depth.
+fn pack_row(indices: &[u8], depth: BitDepth) -> Vec<u8> {
+    match depth {
+        BitDepth::Eight => indices.to_vec(),
+        _ => {
+            let bits = depth as usize;
+            let per_byte = 8 / bits;
+            let mut row = vec![0u8; indices.len().div_ceil(per_byte)];
+            for (i, &index) in indices.iter().enumerate() {
+                let byte = i / per_byte;
+                let shift = 8 - bits * (i % per_byte + 1);
+                row[byte] |= index << shift;
+            }
+            row
+        }
+    }
+}
+
+// Writes `mapping` as a true indexed PNG using `centroids` as the PLTE/tRNS source.
+pub fn write_indexed_png(
+    path: &Path,
+    width: u32,
+    height: u32,
+    centroids: &Dataset,
+    mapping: &[usize],
+    optimize: bool,
+) -> Result<(), Box<dyn Error>> {
+    if centroids.len() > MAX_PALETTE_SIZE {
+        return Err(format!(
+            "palette of {} colors exceeds indexed PNG limit of {}",
+            centroids.len(),
+            MAX_PALETTE_SIZE
+        )
+        .into());
+    }
+
+    let depth = bit_depth_for(centroids.len());
+
+    let mut plte = Vec::with_capacity(centroids.len() * 3);
+    let mut trns = Vec::with_capacity(centroids.len());
+    let mut has_alpha = false;
+    for centroid in centroids {
+        plte.push(centroid[0].round() as u8);
+        plte.push(centroid[1].round() as u8);
+        plte.push(centroid[2].round() as u8);
+        let a = centroid[3].round() as u8;
+        if a != 255 {
+            has_alpha = true;
+        }
+        trns.push(a);
+    }
+
+    let file = File::create(path)?;
+    let writer = BufWriter::new(file);
+    let mut encoder = Encoder::new(writer, width, height);
+    encoder.set_color(ColorType::Indexed);
+    encoder.set_depth(depth);
+    encoder.set_palette(plte);
+    if has_alpha {
+        encoder.set_trns(trns);
+    }
+    apply_optimization(&mut encoder, optimize);
+    let mut writer = encoder.write_header()?;
+
+    let indices: Vec<u8> = mapping.iter().map(|&i| i as u8).collect();
+    let mut data = Vec::with_capacity(indices.len() / (8 / (depth as usize).max(1)) + height as usize);
+    for row in indices.chunks(width as usize) {
+        data.extend(pack_row(row, depth));
+    }
+    writer.write_image_data(&data)?;
+    Ok(())
+}
+
+pub fn fits_indexed_palette(palette_size: usize) -> bool {
+    palette_size <= MAX_PALETTE_SIZE
+}
+
+// Plain RGBA PNG fallback for images that can't be packed into an indexed palette.
+pub fn write_rgba_png(path: &Path, img: &ImageBuffer<Rgba<u8>, Vec<u8>>, optimize: bool) -> Result<(), Box<dyn Error>> {
+    let file = File::create(path)?;
+    let writer = BufWriter::new(file);
+    let mut encoder = Encoder::new(writer, img.width(), img.height());
+    encoder.set_color(ColorType::Rgba);
+    encoder.set_depth(BitDepth::Eight);
+    apply_optimization(&mut encoder, optimize);
+    let mut writer = encoder.write_header()?;
+    writer.write_image_data(img.as_raw())?;
+    Ok(())
+}
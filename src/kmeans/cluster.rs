@@ -20,3 +20,80 @@ pub fn euclidean_distance_squared(a: &[f64; 4], b: &[f64; 4]) -> f64 {
     }
     s
 }
+
+// D65 reference white used by the sRGB <-> CIELAB conversion below.
+const WHITE_X: f64 = 0.95047;
+const WHITE_Y: f64 = 1.0;
+const WHITE_Z: f64 = 1.08883;
+
+fn inverse_gamma(c: f64) -> f64 {
+    if c <= 0.04045 {
+        c / 12.92
+    } else {
+        ((c + 0.055) / 1.055).powf(2.4)
+    }
+}
+
+fn gamma(c: f64) -> f64 {
+    if c <= 0.0031308 {
+        c * 12.92
+    } else {
+        1.055 * c.powf(1.0 / 2.4) - 0.055
+    }
+}
+
+fn lab_f(t: f64) -> f64 {
+    if t > 0.008856 {
+        t.cbrt()
+    } else {
+        7.787 * t + 16.0 / 116.0
+    }
+}
+
+fn lab_f_inv(t: f64) -> f64 {
+    let t3 = t * t * t;
+    if t3 > 0.008856 {
+        t3
+    } else {
+        (t - 16.0 / 116.0) / 7.787
+    }
+}
+
+// sRGB 0-255 pixel to CIELAB, alpha passed through unscaled as the 4th channel.
+pub fn srgb_to_lab(pixel: &[f64; 4]) -> [f64; 4] {
+    let r = inverse_gamma(pixel[0] / 255.0);
+    let g = inverse_gamma(pixel[1] / 255.0);
+    let b = inverse_gamma(pixel[2] / 255.0);
+
+    let x = (0.4124 * r + 0.3576 * g + 0.1805 * b) / WHITE_X;
+    let y = (0.2126 * r + 0.7152 * g + 0.0722 * b) / WHITE_Y;
+    let z = (0.0193 * r + 0.1192 * g + 0.9505 * b) / WHITE_Z;
+
+    let fx = lab_f(x);
+    let fy = lab_f(y);
+    let fz = lab_f(z);
+
+    [116.0 * fy - 16.0, 500.0 * (fx - fy), 200.0 * (fy - fz), pixel[3]]
+}
+
+// Inverse of srgb_to_lab, back to sRGB 0-255.
+pub fn lab_to_srgb(pixel: &[f64; 4]) -> [f64; 4] {
+    let fy = (pixel[0] + 16.0) / 116.0;
+    let fx = fy + pixel[1] / 500.0;
+    let fz = fy - pixel[2] / 200.0;
+
+    let x = lab_f_inv(fx) * WHITE_X;
+    let y = lab_f_inv(fy) * WHITE_Y;
+    let z = lab_f_inv(fz) * WHITE_Z;
+
+    let r = 3.2406 * x - 1.5372 * y - 0.4986 * z;
+    let g = -0.9689 * x + 1.8758 * y + 0.0415 * z;
+    let b = 0.0557 * x - 0.2040 * y + 1.0570 * z;
+
+    [
+        (gamma(r) * 255.0).clamp(0.0, 255.0),
+        (gamma(g) * 255.0).clamp(0.0, 255.0),
+        (gamma(b) * 255.0).clamp(0.0, 255.0),
+        pixel[3],
+    ]
+}
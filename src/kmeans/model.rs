@@ -1,29 +1,57 @@
 use crate::kmeans::cluster::DistanceFunc;
 use rand::Rng;
+#[cfg(feature = "parallel")]
+use rayon::prelude::*;
+#[cfg(feature = "parallel")]
+use std::sync::OnceLock;
 
 pub type Dataset = Vec<[f64; 4]>;
 
+// Bounded rayon pool the inner k-means loop runs on (--kcpu), built once and
+// shared across every fit() call so --concurrency workers don't each spin up
+// their own pool.
+#[cfg(feature = "parallel")]
+static KMEANS_POOL: OnceLock<rayon::ThreadPool> = OnceLock::new();
+
 pub struct Trainer {
     pub k: usize,
     pub distance_fn: DistanceFunc,
     pub max_iterations: usize,
     pub delta: f64,
+    // Size of the shared kmeans thread pool; None lets rayon pick its default.
+    // Only read when the parallel inner loop exists.
+    #[cfg(feature = "parallel")]
+    pub kcpu: Option<usize>,
 }
 
 impl Trainer {
     pub fn fit(&self, data: Dataset) -> Model {
-        let mut model = Model {
+        let model = Model {
             distance_fn: self.distance_fn,
             k: self.k,
             mapping: vec![0; data.len()],
             centroids: vec![[0f64; 4]; self.k],
             iter: 0,
         };
+
+        #[cfg(feature = "parallel")]
+        if let Some(kcpu) = self.kcpu {
+            let pool = KMEANS_POOL.get_or_init(|| {
+                rayon::ThreadPoolBuilder::new()
+                    .num_threads(kcpu)
+                    .build()
+                    .expect("Failed to build kmeans thread pool")
+            });
+            return pool.install(|| self.run(data, model));
+        }
+
+        self.run(data, model)
+    }
+
+    fn run(&self, data: Dataset, mut model: Model) -> Model {
         model.initialize_mean(&data);
 
         let change_threshold = ((data.len() as f64) * self.delta) as usize;
-        let mut cb = vec![0i32; self.k];
-        let mut cn = vec![[0f64; 4]; self.k];
 
         let mut iter = 0;
         loop {
@@ -32,42 +60,15 @@ impl Trainer {
             }
             iter += 1;
 
-            let mut changes = 0;
-            for i in 0..data.len() {
-                let mut m = (self.distance_fn)(&model.centroids[0], &data[i]);
-                let mut n = 0;
-                for j in 1..self.k {
-                    let d = (self.distance_fn)(&model.centroids[j], &data[i]);
-                    if d < m {
-                        m = d;
-                        n = j;
-                    }
-                }
-
-                if model.mapping[i] != n {
-                    changes += 1;
-                }
-                model.mapping[i] = n;
-                cb[n] += 1;
-
-                cn[n][0] += &data[i][0];
-                cn[n][1] += &data[i][1];
-                cn[n][2] += &data[i][2];
-                cn[n][3] += &data[i][3];
-            }
+            let (cb, cn, changes) = assign(self.distance_fn, &model.centroids, &data, &mut model.mapping);
 
             for i in 0..self.k {
+                if cb[i] == 0 {
+                    continue;
+                }
                 let scale = 1.0 / (cb[i] as f64);
-                cb[i] = 0;
-
-                cn[i][0] *= scale;
-                cn[i][1] *= scale;
-                cn[i][2] *= scale;
-                cn[i][3] *= scale;
-
                 for j in 0..4 {
-                    model.centroids[i][j] = cn[i][j];
-                    cn[i][j] = 0.0
+                    model.centroids[i][j] = cn[i][j] * scale;
                 }
             }
 
@@ -81,6 +82,88 @@ impl Trainer {
     }
 }
 
+fn nearest_centroid(distance_fn: DistanceFunc, centroids: &[[f64; 4]], pixel: &[f64; 4]) -> usize {
+    let mut m = distance_fn(&centroids[0], pixel);
+    let mut n = 0;
+    for j in 1..centroids.len() {
+        let d = distance_fn(&centroids[j], pixel);
+        if d < m {
+            m = d;
+            n = j;
+        }
+    }
+    n
+}
+
+// Data-parallel nearest-centroid assignment, reduced from each chunk's partial sums.
+#[cfg(feature = "parallel")]
+fn assign(
+    distance_fn: DistanceFunc,
+    centroids: &[[f64; 4]],
+    data: &Dataset,
+    mapping: &mut [usize],
+) -> (Vec<i32>, Vec<[f64; 4]>, usize) {
+    let k = centroids.len();
+    data.par_iter()
+        .zip(mapping.par_iter_mut())
+        .fold(
+            || (vec![0i32; k], vec![[0f64; 4]; k], 0usize),
+            |(mut cb, mut cn, mut changes), (pixel, slot)| {
+                let n = nearest_centroid(distance_fn, centroids, pixel);
+                if *slot != n {
+                    changes += 1;
+                }
+                *slot = n;
+                cb[n] += 1;
+                for c in 0..4 {
+                    cn[n][c] += pixel[c];
+                }
+                (cb, cn, changes)
+            },
+        )
+        .reduce(|| (vec![0i32; k], vec![[0f64; 4]; k], 0usize), merge_partials)
+}
+
+#[cfg(not(feature = "parallel"))]
+fn assign(
+    distance_fn: DistanceFunc,
+    centroids: &[[f64; 4]],
+    data: &Dataset,
+    mapping: &mut [usize],
+) -> (Vec<i32>, Vec<[f64; 4]>, usize) {
+    let k = centroids.len();
+    let mut cb = vec![0i32; k];
+    let mut cn = vec![[0f64; 4]; k];
+    let mut changes = 0;
+    for i in 0..data.len() {
+        let n = nearest_centroid(distance_fn, centroids, &data[i]);
+        if mapping[i] != n {
+            changes += 1;
+        }
+        mapping[i] = n;
+        cb[n] += 1;
+        for c in 0..4 {
+            cn[n][c] += data[i][c];
+        }
+    }
+    (cb, cn, changes)
+}
+
+#[cfg(feature = "parallel")]
+fn merge_partials(
+    mut a: (Vec<i32>, Vec<[f64; 4]>, usize),
+    b: (Vec<i32>, Vec<[f64; 4]>, usize),
+) -> (Vec<i32>, Vec<[f64; 4]>, usize) {
+    for i in 0..a.0.len() {
+        a.0[i] += b.0[i];
+        for c in 0..4 {
+            a.1[i][c] += b.1[i][c];
+        }
+    }
+    a.2 += b.2;
+    a
+}
+
 pub struct Model {
     distance_fn: DistanceFunc,
     k: usize,
@@ -94,31 +177,113 @@ impl Model {
         self.centroids[0] = data[rand::rng().random_range(0..data.len())];
         let mut d = vec![0f64; data.len()];
         for i in 1..self.k {
-            let mut s = 0f64;
-            for j in 0..data.len() {
-                let mut l = (self.distance_fn)(&self.centroids[0], &data[j]);
-                for g in 1..i {
-                    let f = (self.distance_fn)(&self.centroids[g], &data[j]);
-                    if f < l {
-                        l = f
+            let centroids = &self.centroids;
+            let distance_fn = self.distance_fn;
+
+            #[cfg(feature = "parallel")]
+            let s: f64 = d
+                .par_iter_mut()
+                .zip(data.par_iter())
+                .map(|(slot, pixel)| {
+                    let mut l = distance_fn(&centroids[0], pixel);
+                    for g in 1..i {
+                        let f = distance_fn(&centroids[g], pixel);
+                        if f < l {
+                            l = f
+                        }
                     }
-                }
+                    *slot = l * l;
+                    *slot
+                })
+                .sum();
 
-                d[j] = l * l;
-                s += d[j];
-            }
+            #[cfg(not(feature = "parallel"))]
+            let s: f64 = {
+                let mut s = 0f64;
+                for j in 0..data.len() {
+                    let mut l = distance_fn(&centroids[0], &data[j]);
+                    for g in 1..i {
+                        let f = distance_fn(&centroids[g], &data[j]);
+                        if f < l {
+                            l = f
+                        }
+                    }
+                    d[j] = l * l;
+                    s += d[j];
+                }
+                s
+            };
 
             let t = rand::rng().random_range(0.0..1.0) * s;
             let mut k = 0;
-            let mut s = d[0];
+            let mut acc = d[0];
             loop {
-                if s >= t {
+                if acc >= t || k >= data.len() - 1 {
                     break;
                 }
                 k += 1;
-                s += d[k];
+                acc += d[k];
             }
             self.centroids[i] = data[k]
         }
     }
+
+    // Re-assign each pixel by nearest centroid to its Floyd-Steinberg error-adjusted
+    // color, diffusing the per-channel error to neighboring pixels as it goes. `data`
+    // must be in raster order and the same color space as `self.centroids`.
+    pub fn dither(&self, data: Dataset, width: u32, height: u32) -> Vec<usize> {
+        let mut working = data;
+        let mut mapping = vec![0usize; working.len()];
+
+        for y in 0..height {
+            for x in 0..width {
+                let i = (y * width + x) as usize;
+                let pixel = [
+                    working[i][0].clamp(0.0, 255.0),
+                    working[i][1].clamp(0.0, 255.0),
+                    working[i][2].clamp(0.0, 255.0),
+                    working[i][3].clamp(0.0, 255.0),
+                ];
+
+                let n = nearest_centroid(self.distance_fn, &self.centroids, &pixel);
+                mapping[i] = n;
+
+                let chosen = self.centroids[n];
+                let mut error = [0f64; 4];
+                for c in 0..4 {
+                    error[c] = pixel[c] - chosen[c];
+                }
+
+                diffuse_error(&mut working, width, height, x, y, 1, 0, error, 7.0 / 16.0);
+                diffuse_error(&mut working, width, height, x, y, -1, 1, error, 3.0 / 16.0);
+                diffuse_error(&mut working, width, height, x, y, 0, 1, error, 5.0 / 16.0);
+                diffuse_error(&mut working, width, height, x, y, 1, 1, error, 1.0 / 16.0);
+            }
+        }
+
+        mapping
+    }
+}
+
+#[allow(clippy::too_many_arguments)]
+fn diffuse_error(
+    working: &mut [[f64; 4]],
+    width: u32,
+    height: u32,
+    x: u32,
+    y: u32,
+    dx: i32,
+    dy: i32,
+    error: [f64; 4],
+    weight: f64,
+) {
+    let nx = x as i64 + dx as i64;
+    let ny = y as i64 + dy as i64;
+    if nx < 0 || ny < 0 || nx >= width as i64 || ny >= height as i64 {
+        return;
+    }
+    let i = (ny as u32 * width + nx as u32) as usize;
+    for c in 0..4 {
+        working[i][c] += error[c] * weight;
+    }
 }